@@ -6,11 +6,17 @@
 //! Preimage for commitment (canonical, built off-chain): claim_id + "\n" + execution_ref + "\n"
 //! + amount + "\n" + token_symbol + "\n" + token_chain + "\n" + (recipient_id or "") + "\n" + nonce_hex.
 //! Verification: recompute hash from off-chain data + stored nonce; must equal on-chain commitment.
+//!
+//! Access control: role-based (see `Role`) rather than a single allowed caller. The deploying
+//! account is always an implicit Admin; Admins grant/revoke Authorizer and Executor roles.
 
 use borsh::{BorshDeserialize, BorshSchema, BorshSerialize};
 use near_sdk::env;
+use near_sdk::json_types::U128;
 use near_sdk::near;
 use near_sdk::serde::Serialize;
+use near_sdk::store::LookupMap;
+use near_sdk::{AccountId, BorshStorageKey, NearToken, Promise, PromiseResult};
 use schemars::JsonSchema;
 
 /// 32-byte commitment (e.g. SHA256 of canonical preimage). No plaintext amount/token/recipient on-chain.
@@ -25,6 +31,7 @@ pub struct PaymentAttestation {
 }
 
 /// Receipt record for deal payments / payroll runs (hash-only, no amounts). Used by PayrollReceiptLoggerService.
+/// `prev_hash`/`entry_hash` chain this record into its authorizer's tamper-evident history (see `verify_chain`).
 #[derive(BorshDeserialize, BorshSerialize, BorshSchema, Serialize, Clone, JsonSchema)]
 #[serde(crate = "near_sdk::serde")]
 pub struct ReceiptRecord {
@@ -36,43 +43,439 @@ pub struct ReceiptRecord {
     pub status: String,
     pub tx_refs_hash: String,
     pub timestamp_nanos: u64,
+    /// `head_hash` for this authorizer immediately before this record was appended.
+    pub prev_hash: [u8; 32],
+    /// sha256(prev_hash || payroll_id || batch_hash || nonce || status || tx_refs_hash || timestamp_nanos).
+    pub entry_hash: [u8; 32],
+}
+
+/// A role grantable to an account. `Admin` can grant/revoke roles; the owner is always an implicit Admin.
+#[derive(
+    BorshDeserialize, BorshSerialize, BorshSchema, Serialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, JsonSchema,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub enum Role {
+    /// May authorize (be the `authorizer_id` on) a payroll receipt.
+    Authorizer,
+    /// May post receipts for payouts it performed (the predecessor on `record_receipt`/`record_payment`).
+    Executor,
+    /// May grant/revoke roles.
+    Admin,
+}
+
+/// Lifecycle state of a per-authorizer nonce.
+#[derive(BorshDeserialize, BorshSerialize, BorshSchema, Serialize, Clone, Copy, PartialEq, Eq, Debug, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+pub enum NonceStatus {
+    /// Never reserved (or reserved and then released).
+    Free,
+    /// Reserved by `executor_id` at `reserved_at`, awaiting a matching `record_receipt`.
+    Reserved,
+    /// Consumed by a posted receipt.
+    Used,
+}
+
+/// A nonce's reservation, tracked from `reserve_nonce` through `record_receipt`/`release_nonce`.
+#[derive(BorshDeserialize, BorshSerialize, BorshSchema, Serialize, Clone, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+pub struct NonceReservation {
+    pub executor_id: String,
+    pub status: NonceStatus,
+    pub reserved_at: u64,
+}
+
+/// One step in a receipt's status-transition history, recorded when a retry advances its status.
+#[derive(BorshDeserialize, BorshSerialize, BorshSchema, Serialize, Clone, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ReceiptTransition {
+    pub old_status: String,
+    pub new_status: String,
+    pub executor_id: String,
+    pub timestamp_nanos: u64,
+}
+
+/// Status transitions a retried receipt post may legally make. Anything else (including
+/// re-posting the same status, or moving backwards) is rejected.
+const LEGAL_STATUS_TRANSITIONS: [(&str, &str); 3] = [
+    ("failed", "partial"),
+    ("partial", "success"),
+    ("failed", "success"),
+];
+
+/// Default time a reservation stays exclusive to its executor before another executor may
+/// reclaim it (5 minutes in nanoseconds).
+const DEFAULT_RESERVATION_TTL_NANOS: u64 = 5 * 60 * 1_000_000_000;
+
+/// A batch payroll run lock for an authorizer, held by the executor driving it.
+#[derive(BorshDeserialize, BorshSerialize, BorshSchema, Serialize, Clone, JsonSchema)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RunLock {
+    pub run_id: String,
+    pub executor_id: String,
+    pub started_at: u64,
+}
+
+/// Default time a run lock may be held before it's considered stale and forcibly reclaimable
+/// (30 minutes in nanoseconds).
+const DEFAULT_RUN_LOCK_TIMEOUT_NANOS: u64 = 30 * 60 * 1_000_000_000;
+
+/// Storage key prefixes for the persistent collections below. Each collection gets its own
+/// prefix so only the keys actually touched by a call are read from / written to storage.
+#[derive(BorshSerialize, BorshStorageKey)]
+pub enum StorageKey {
+    Payments,
+    Receipts,
+    Roles,
+    NonceReservations,
+    NextNonce,
+    HeadHash,
+    ReceiptHistory,
+    ActiveRuns,
 }
 
 #[near(contract_state)]
-#[derive(Default)]
 pub struct PayrollAttestation {
     owner_id: String,
-    /// Only this account (or empty = any) can call record_payment and record_receipt.
-    allowed_caller: Option<String>,
-    payments: std::collections::HashMap<String, PaymentAttestation>,
-    /// Deal payment / payroll run receipts (key = payroll_id). Idempotent: same payroll_id is a no-op.
-    receipts: std::collections::HashMap<String, ReceiptRecord>,
-    /// Used (authorizer_id, nonce) to prevent duplicate receipt posts. Key format "authorizer_id::nonce".
-    used_receipt_nonces: std::collections::HashSet<String>,
+    payments: LookupMap<String, PaymentAttestation>,
+    /// Deal payment / payroll run receipts (key = payroll_id). Re-posting the same status is a
+    /// no-op; re-posting a different status is a retry and must advance a legal transition (see
+    /// `record_receipt`).
+    receipts: LookupMap<String, ReceiptRecord>,
+    /// Role grants, keyed by (role, account_id). The owner is always treated as an Admin in addition to this set.
+    roles: LookupMap<(Role, String), ()>,
+    /// Reservation state for each (authorizer_id, nonce) that has been reserved or used.
+    nonce_reservations: LookupMap<(String, u64), NonceReservation>,
+    /// Next nonce to hand out per authorizer_id.
+    next_nonce: LookupMap<String, u64>,
+    /// How long a reservation stays exclusive to its executor before it becomes reclaimable.
+    reservation_ttl_nanos: u64,
+    /// Latest `entry_hash` appended to each authorizer's receipt chain. Absent = genesis (all-zero).
+    head_hash: LookupMap<String, [u8; 32]>,
+    /// Status-transition history per payroll_id, oldest first. Only populated once a payroll_id
+    /// has been re-posted with an advancing status.
+    receipt_history: LookupMap<String, Vec<ReceiptTransition>>,
+    /// Fee (in yoctoNEAR) that `record_payment`/`record_receipt` must attach. Zero = no fee.
+    record_fee: u128,
+    /// Total fees collected over the contract's lifetime, in yoctoNEAR.
+    fees_collected: u128,
+    /// Total fees withdrawn by the owner over the contract's lifetime, in yoctoNEAR.
+    fees_withdrawn: u128,
+    /// Active batch payroll run per authorizer_id, held from `begin_run` until `record_receipt`
+    /// completes it (or it goes stale and is reclaimed).
+    active_runs: LookupMap<String, RunLock>,
+    /// How long a run lock may be held before it's considered stale and forcibly reclaimable.
+    run_lock_timeout_nanos: u64,
+}
+
+impl Default for PayrollAttestation {
+    fn default() -> Self {
+        env::panic_str("PayrollAttestation must be initialized with new()");
+    }
 }
 
 #[near]
 impl PayrollAttestation {
-    /// Initialize. Pass allowed_caller as a string (backend account ID); empty string = any caller.
+    /// Initialize. The deploying account becomes the owner and bootstrap Admin.
     #[init]
-    pub fn new(allowed_caller: String) -> Self {
+    pub fn new() -> Self {
         let owner_id = env::predecessor_account_id().to_string();
-        let allowed_caller = if allowed_caller.is_empty() {
-            None
-        } else {
-            Some(allowed_caller)
-        };
         Self {
             owner_id,
-            allowed_caller,
-            payments: std::collections::HashMap::new(),
-            receipts: std::collections::HashMap::new(),
-            used_receipt_nonces: std::collections::HashSet::new(),
+            payments: LookupMap::new(StorageKey::Payments),
+            receipts: LookupMap::new(StorageKey::Receipts),
+            roles: LookupMap::new(StorageKey::Roles),
+            nonce_reservations: LookupMap::new(StorageKey::NonceReservations),
+            next_nonce: LookupMap::new(StorageKey::NextNonce),
+            reservation_ttl_nanos: DEFAULT_RESERVATION_TTL_NANOS,
+            head_hash: LookupMap::new(StorageKey::HeadHash),
+            receipt_history: LookupMap::new(StorageKey::ReceiptHistory),
+            record_fee: 0,
+            fees_collected: 0,
+            fees_withdrawn: 0,
+            active_runs: LookupMap::new(StorageKey::ActiveRuns),
+            run_lock_timeout_nanos: DEFAULT_RUN_LOCK_TIMEOUT_NANOS,
+        }
+    }
+
+    /// sha256(prev || payroll_id || batch_hash || nonce || status || tx_refs_hash || timestamp_nanos).
+    fn compute_entry_hash(
+        prev: &[u8; 32],
+        payroll_id: &str,
+        batch_hash: &str,
+        nonce: u64,
+        status: &str,
+        tx_refs_hash: &str,
+        timestamp_nanos: u64,
+    ) -> [u8; 32] {
+        let mut preimage = Vec::new();
+        preimage.extend_from_slice(prev);
+        preimage.extend_from_slice(payroll_id.as_bytes());
+        preimage.extend_from_slice(batch_hash.as_bytes());
+        preimage.extend_from_slice(&nonce.to_le_bytes());
+        preimage.extend_from_slice(status.as_bytes());
+        preimage.extend_from_slice(tx_refs_hash.as_bytes());
+        preimage.extend_from_slice(&timestamp_nanos.to_le_bytes());
+        env::sha256(&preimage)
+            .try_into()
+            .expect("env::sha256 returns 32 bytes")
+    }
+
+    /// The current head of `authorizer_id`'s receipt chain (all-zero if it has no receipts yet).
+    pub fn get_chain_head(&self, authorizer_id: String) -> [u8; 32] {
+        self.head_hash.get(&authorizer_id).copied().unwrap_or([0u8; 32])
+    }
+
+    /// Recompute `authorizer_id`'s receipt chain from `receipts` (the run proving the chain
+    /// from `from_nonce`'s position up to the current head) and confirm it reproduces the
+    /// current on-chain `head_hash`. Chain position is established purely through the
+    /// `prev_hash`/`entry_hash` links, not through nonce order: nonces are reserved ahead of
+    /// run boundaries and aren't guaranteed to be consumed in reservation order, so a nonce
+    /// contiguity check would false-flag an honest history as tampered.
+    /// Any insertion, deletion, or mutation of a historical receipt breaks the chain and returns false.
+    pub fn verify_chain(
+        &self,
+        authorizer_id: String,
+        from_nonce: u64,
+        receipts: Vec<ReceiptRecord>,
+    ) -> bool {
+        let mut prev = if from_nonce == 0 {
+            [0u8; 32]
+        } else {
+            match receipts.first() {
+                Some(r) => r.prev_hash,
+                None => return false,
+            }
+        };
+        for r in receipts.iter() {
+            if r.authorizer_id != authorizer_id {
+                return false;
+            }
+            if r.prev_hash != prev {
+                return false;
+            }
+            let entry_hash = Self::compute_entry_hash(
+                &prev,
+                &r.payroll_id,
+                &r.batch_hash,
+                r.nonce,
+                &r.status,
+                &r.tx_refs_hash,
+                r.timestamp_nanos,
+            );
+            if entry_hash != r.entry_hash {
+                return false;
+            }
+            prev = entry_hash;
         }
+        prev == self.get_chain_head(authorizer_id)
+    }
+
+    /// Set how long a reservation stays exclusive to its executor before it becomes reclaimable. Admin only.
+    pub fn set_reservation_ttl(&mut self, ttl_nanos: u64) {
+        self.assert_admin();
+        self.reservation_ttl_nanos = ttl_nanos;
+    }
+
+    /// Atomically allocate the next nonce for `authorizer_id` and mark it Reserved by the
+    /// calling executor. Caller must hold the Executor role.
+    pub fn reserve_nonce(&mut self, authorizer_id: String) -> u64 {
+        let caller = env::predecessor_account_id().to_string();
+        self.assert_role(&caller, Role::Executor);
+        let nonce = self.next_nonce.get(&authorizer_id).copied().unwrap_or(0);
+        self.next_nonce.insert(authorizer_id.clone(), nonce + 1);
+        self.nonce_reservations.insert(
+            (authorizer_id, nonce),
+            NonceReservation {
+                executor_id: caller,
+                status: NonceStatus::Reserved,
+                reserved_at: env::block_timestamp(),
+            },
+        );
+        nonce
+    }
+
+    /// Free a reservation that was never executed. The reserving executor may release it at
+    /// any time; any other caller may only reclaim it once the reservation TTL has elapsed.
+    pub fn release_nonce(&mut self, authorizer_id: String, nonce: u64) {
+        let caller = env::predecessor_account_id().to_string();
+        let key = (authorizer_id, nonce);
+        let reservation = self
+            .nonce_reservations
+            .get(&key)
+            .cloned()
+            .unwrap_or_else(|| env::panic_str("No reservation for this nonce"));
+        assert_eq!(
+            reservation.status,
+            NonceStatus::Reserved,
+            "Nonce is not in Reserved state"
+        );
+        let stale = env::block_timestamp().saturating_sub(reservation.reserved_at)
+            >= self.reservation_ttl_nanos;
+        assert!(
+            reservation.executor_id == caller || stale,
+            "Only the reserving executor can release before the reservation TTL expires"
+        );
+        self.nonce_reservations.remove(&key);
+    }
+
+    /// Current lifecycle state of an authorizer's nonce.
+    pub fn nonce_status(&self, authorizer_id: String, nonce: u64) -> NonceStatus {
+        self.nonce_reservations
+            .get(&(authorizer_id, nonce))
+            .map_or(NonceStatus::Free, |r| r.status)
+    }
+
+    /// Set how long a run lock may be held before it's considered stale and forcibly
+    /// reclaimable. Admin only.
+    pub fn set_run_lock_timeout(&mut self, timeout_nanos: u64) {
+        self.assert_admin();
+        self.run_lock_timeout_nanos = timeout_nanos;
+    }
+
+    /// Begin a batch payroll run for `authorizer_id`, locking it to `run_id` until
+    /// `record_receipt` completes the run. Caller must hold the Executor role. Rejects an
+    /// overlapping run unless the existing lock is older than `run_lock_timeout_nanos`, in which
+    /// case it is forcibly reclaimed.
+    pub fn begin_run(&mut self, authorizer_id: String, run_id: String) {
+        let caller = env::predecessor_account_id().to_string();
+        self.assert_role(&caller, Role::Executor);
+        if let Some(existing) = self.active_runs.get(&authorizer_id) {
+            let stale = env::block_timestamp().saturating_sub(existing.started_at)
+                >= self.run_lock_timeout_nanos;
+            if !stale {
+                env::log_str(&format!(
+                    "begin_run refused for {}: run {} is already active (started by {})",
+                    authorizer_id, existing.run_id, existing.executor_id
+                ));
+                env::panic_str("A run is already active for this authorizer");
+            }
+            env::log_str(&format!(
+                "begin_run reclaiming stale run {} for {} (was started by {})",
+                existing.run_id, authorizer_id, existing.executor_id
+            ));
+        }
+        self.active_runs.insert(
+            authorizer_id,
+            RunLock {
+                run_id,
+                executor_id: caller,
+                started_at: env::block_timestamp(),
+            },
+        );
+    }
+
+    /// The active run lock for `authorizer_id`, if any.
+    pub fn current_run(&self, authorizer_id: String) -> Option<RunLock> {
+        self.active_runs.get(&authorizer_id).cloned()
+    }
+
+    /// Grant `role` to `account_id`. Admin only.
+    pub fn grant_role(&mut self, account_id: String, role: Role) {
+        self.assert_admin();
+        self.roles.insert((role, account_id), ());
+    }
+
+    /// Revoke `role` from `account_id`. Admin only.
+    pub fn revoke_role(&mut self, account_id: String, role: Role) {
+        self.assert_admin();
+        self.roles.remove(&(role, account_id));
+    }
+
+    /// Whether `account_id` holds `role` (the owner always holds Admin).
+    pub fn has_role(&self, account_id: String, role: Role) -> bool {
+        (role == Role::Admin && account_id == self.owner_id)
+            || self.roles.contains_key(&(role, account_id))
+    }
+
+    fn assert_admin(&self) {
+        let caller = env::predecessor_account_id().to_string();
+        assert!(self.has_role(caller, Role::Admin), "Only an admin can do this");
+    }
+
+    fn assert_role(&self, account_id: &str, role: Role) {
+        assert!(
+            self.has_role(account_id.to_string(), role),
+            "{} does not hold the {:?} role",
+            account_id,
+            role
+        );
+    }
+
+    // ----- Recording fee. -----
+
+    /// Set the fee (in yoctoNEAR) that `record_payment`/`record_receipt` must attach. Admin only.
+    pub fn set_record_fee(&mut self, amount: U128) {
+        self.assert_admin();
+        self.record_fee = amount.0;
+    }
+
+    /// The fee currently required on `record_payment`/`record_receipt`, in yoctoNEAR.
+    pub fn get_record_fee(&self) -> U128 {
+        U128(self.record_fee)
+    }
+
+    /// Total fees collected over the contract's lifetime, in yoctoNEAR.
+    pub fn get_fees_collected(&self) -> U128 {
+        U128(self.fees_collected)
+    }
+
+    /// Total fees withdrawn by the owner over the contract's lifetime, in yoctoNEAR.
+    pub fn get_fees_withdrawn(&self) -> U128 {
+        U128(self.fees_withdrawn)
+    }
+
+    /// Withdraw accrued, unwithdrawn fees to `to`. Owner only. `fees_withdrawn` is reserved
+    /// synchronously (so a second call can't double-spend the same fees while the transfer is
+    /// in flight) and only reverted if the transfer itself fails (see `on_withdraw_fees`).
+    pub fn withdraw_fees(&mut self, to: String) -> Promise {
+        assert_eq!(
+            env::predecessor_account_id().to_string(),
+            self.owner_id,
+            "Only the owner can withdraw fees"
+        );
+        let available = self.fees_collected.saturating_sub(self.fees_withdrawn);
+        assert!(available > 0, "No fees available to withdraw");
+        self.fees_withdrawn = self.fees_withdrawn.saturating_add(available);
+        let to: AccountId = to
+            .parse()
+            .unwrap_or_else(|_| env::panic_str("invalid account_id"));
+        Promise::new(to)
+            .transfer(NearToken::from_yoctonear(available))
+            .then(Self::ext(env::current_account_id()).on_withdraw_fees(U128(available)))
+    }
+
+    /// Callback for `withdraw_fees`. Reverts the `fees_withdrawn` reservation if the transfer failed.
+    #[private]
+    pub fn on_withdraw_fees(&mut self, amount: U128) {
+        if !matches!(env::promise_result(0), PromiseResult::Successful(_)) {
+            self.fees_withdrawn = self.fees_withdrawn.saturating_sub(amount.0);
+            env::log_str(&format!(
+                "withdraw_fees transfer of {} yoctoNEAR failed; fee is withdrawable again",
+                amount.0
+            ));
+        }
+    }
+
+    /// Assert the attached deposit covers `record_fee`, refund any excess to the caller, and add
+    /// the fee to `fees_collected`. Call from a `#[payable]` method after its role checks.
+    fn collect_record_fee(&mut self) {
+        let deposit = env::attached_deposit().as_yoctonear();
+        assert!(
+            deposit >= self.record_fee,
+            "Attached deposit {} is less than the required record fee {}",
+            deposit,
+            self.record_fee
+        );
+        let refund = deposit - self.record_fee;
+        if refund > 0 {
+            Promise::new(env::predecessor_account_id()).transfer(NearToken::from_yoctonear(refund));
+        }
+        self.fees_collected = self.fees_collected.saturating_add(self.record_fee);
     }
 
     /// Record a payment attestation. Idempotent by claim_id. Only commitment is stored (no plaintext amount/token/recipient).
     /// Commitment must be SHA256 of canonical preimage (see PRIVACY.md / module docs).
+    /// Caller must hold the Executor role. Must attach at least `record_fee` (excess is refunded).
     #[payable]
     pub fn record_payment(
         &mut self,
@@ -80,10 +483,9 @@ impl PayrollAttestation {
         execution_ref: String,
         commitment: Vec<u8>,
     ) {
-        if let Some(ref allowed) = self.allowed_caller {
-            let caller = env::predecessor_account_id().to_string();
-            assert_eq!(caller, *allowed, "Only the allowed caller can record payments");
-        }
+        let caller = env::predecessor_account_id().to_string();
+        self.assert_role(&caller, Role::Executor);
+        self.collect_record_fee();
         assert_eq!(
             commitment.len(),
             32,
@@ -112,18 +514,18 @@ impl PayrollAttestation {
         self.payments.get(&claim_id).cloned()
     }
 
-    pub fn set_allowed_caller(&mut self, account_id: Option<String>) {
-        assert_eq!(
-            env::predecessor_account_id().to_string(),
-            self.owner_id,
-            "Only owner can set allowed caller"
-        );
-        self.allowed_caller = account_id;
-    }
-
-    // ----- Receipt logger (deal payments / payroll runs). Same allowed_caller as record_payment. -----
+    // ----- Receipt logger (deal payments / payroll runs). -----
 
-    /// Record a receipt. Idempotent by payroll_id (duplicate payroll_id is a no-op). Caller must be allowed_caller.
+    /// Record a receipt, or retry one that previously failed or partially succeeded.
+    /// `authorizer_id` must hold the Authorizer role; the predecessor must hold the Executor role
+    /// and must present a `nonce` it currently holds Reserved via `reserve_nonce`, and the
+    /// `run_id` of an active `begin_run` lock on `authorizer_id`, which this call clears.
+    ///
+    /// Re-posting an existing `payroll_id` with the same `status` is a no-op. Re-posting with a
+    /// different status is only allowed along a legal transition (`failed -> partial`,
+    /// `partial -> success`, `failed -> success`) and must carry the receipt's original
+    /// `authorizer_id`; the transition is appended to `get_receipt_history(payroll_id)`.
+    /// Must attach at least `record_fee` (excess is refunded).
     #[payable]
     pub fn record_receipt(
         &mut self,
@@ -134,41 +536,117 @@ impl PayrollAttestation {
         executor_id: String,
         status: String,
         tx_refs_hash: String,
+        run_id: String,
     ) {
-        if let Some(ref allowed) = self.allowed_caller {
-            let caller = env::predecessor_account_id().to_string();
-            assert_eq!(caller, *allowed, "Only the allowed caller can record receipts");
-        }
-        if self.receipts.contains_key(&payroll_id) {
-            return;
-        }
-        let nonce_key = format!("{}::{}", authorizer_id, nonce);
-        if self.used_receipt_nonces.contains(&nonce_key) {
-            near_sdk::env::panic_str("Nonce already used for this authorizer");
+        self.assert_role(&authorizer_id, Role::Authorizer);
+        let caller = env::predecessor_account_id().to_string();
+        self.assert_role(&caller, Role::Executor);
+        self.collect_record_fee();
+        let active_run = self
+            .active_runs
+            .get(&authorizer_id)
+            .cloned()
+            .unwrap_or_else(|| env::panic_str("No active run for this authorizer"));
+        assert_eq!(
+            active_run.run_id, run_id,
+            "run_id does not match the active run for this authorizer"
+        );
+        let key = (authorizer_id.clone(), nonce);
+        let reservation = self
+            .nonce_reservations
+            .get(&key)
+            .cloned()
+            .unwrap_or_else(|| env::panic_str("Nonce was not reserved for this authorizer"));
+        assert_eq!(
+            reservation.status,
+            NonceStatus::Reserved,
+            "Nonce is not in Reserved state"
+        );
+        assert_eq!(
+            reservation.executor_id, caller,
+            "Nonce was reserved by a different executor"
+        );
+        let existing = self.receipts.get(&payroll_id).cloned();
+        if let Some(existing) = &existing {
+            assert_eq!(
+                existing.authorizer_id, authorizer_id,
+                "Retrying a receipt must carry its original authorizer_id"
+            );
+            if existing.status == status {
+                self.nonce_reservations.insert(
+                    key,
+                    NonceReservation {
+                        status: NonceStatus::Used,
+                        ..reservation
+                    },
+                );
+                self.active_runs.remove(&authorizer_id);
+                return;
+            }
+            assert!(
+                LEGAL_STATUS_TRANSITIONS.contains(&(existing.status.as_str(), status.as_str())),
+                "Illegal status transition from {} to {}",
+                existing.status,
+                status
+            );
         }
         let timestamp_nanos = env::block_timestamp();
+        let prev_hash = self.get_chain_head(authorizer_id.clone());
+        let entry_hash = Self::compute_entry_hash(
+            &prev_hash,
+            &payroll_id,
+            &batch_hash,
+            nonce,
+            &status,
+            &tx_refs_hash,
+            timestamp_nanos,
+        );
         self.receipts.insert(
             payroll_id.clone(),
             ReceiptRecord {
-                payroll_id,
+                payroll_id: payroll_id.clone(),
                 batch_hash,
                 authorizer_id: authorizer_id.clone(),
                 nonce,
                 executor_id,
-                status,
+                status: status.clone(),
                 tx_refs_hash,
                 timestamp_nanos,
+                prev_hash,
+                entry_hash,
+            },
+        );
+        self.active_runs.remove(&authorizer_id);
+        self.head_hash.insert(authorizer_id, entry_hash);
+        self.nonce_reservations.insert(
+            key,
+            NonceReservation {
+                status: NonceStatus::Used,
+                ..reservation
             },
         );
-        self.used_receipt_nonces.insert(nonce_key);
+        if let Some(existing) = existing {
+            let mut history = self.receipt_history.get(&payroll_id).cloned().unwrap_or_default();
+            history.push(ReceiptTransition {
+                old_status: existing.status,
+                new_status: status,
+                executor_id: caller,
+                timestamp_nanos,
+            });
+            self.receipt_history.insert(payroll_id, history);
+        }
     }
 
     pub fn get_receipt(&self, payroll_id: String) -> Option<ReceiptRecord> {
         self.receipts.get(&payroll_id).cloned()
     }
 
+    /// Full status-transition history for a payroll_id, oldest first (empty if it has never been retried).
+    pub fn get_receipt_history(&self, payroll_id: String) -> Vec<ReceiptTransition> {
+        self.receipt_history.get(&payroll_id).cloned().unwrap_or_default()
+    }
+
     pub fn is_nonce_used(&self, authorizer_id: String, nonce: u64) -> bool {
-        self.used_receipt_nonces
-            .contains(&format!("{}::{}", authorizer_id, nonce))
+        self.nonce_status(authorizer_id, nonce) == NonceStatus::Used
     }
 }